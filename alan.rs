@@ -1,142 +1,2192 @@
 use std::fs;
 use std::result;
-use std::fmt::Write;
+use std::fmt::{self, Write};
 use std::env;
 use std::iter::Peekable;
 use std::process::ExitCode;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use std::ops::Range;
 
 type Result<T> = result::Result<T, ()>;
 
-#[derive(Debug, PartialEq)]
-struct Symbol<'a> {
-    name: &'a str,
+thread_local! {
+    // Every symbol name is stored once and numbered in the order it was
+    // first seen.
+    static NAMES: RefCell<HashMap<Rc<str>, u32>> = RefCell::new(HashMap::new());
 }
 
-#[derive(Debug)]
+fn intern(name: &str) -> (Rc<str>, u32) {
+    NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+        if let Some((name, &id)) = names.get_key_value(name) {
+            return (name.clone(), id);
+        }
+        let id = names.len() as u32;
+        let name: Rc<str> = name.into();
+        names.insert(name.clone(), id);
+        (name, id)
+    })
+}
+
+// Symbols are interned, so comparing and hashing them only looks at the id.
+#[derive(Debug, Clone)]
+struct Symbol {
+    name: Rc<str>,
+    id: u32,
+    // Whether the name has `$var` references to substitute. Quoted symbols
+    // are taken as written, so a `$` in them is never a variable.
+    template: bool,
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// Symbols are quoted when they would not read back as the same symbol.
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_symbol(f, &self.name)
+    }
+}
+
+fn write_symbol(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    // Tape files also read `x*n` as a repeat and `[x]` as the head.
+    let needs_quotes = name.is_empty() || name == "|" || name.contains('*')
+        || (name.starts_with('[') && name.ends_with(']'))
+        || name.char_indices().any(|(i, x)| {
+            x.is_whitespace() || is_punct(x) || x == '"' || is_comment(&name[i..])
+        });
+    if !needs_quotes {
+        return write!(f, "{name}");
+    }
+
+    write!(f, "\"")?;
+    for x in name.chars() {
+        match x {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            x => write!(f, "{x}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl Symbol {
+    fn new(name: &str) -> Self {
+        let (name, id) = intern(name);
+        let template = Self::find_var(&name).is_some();
+        Self{name, id, template}
+    }
+
+    fn literal(name: &str) -> Self {
+        let (name, id) = intern(name);
+        Self{name, id, template: false}
+    }
+
+    // Returns the byte range of the first `$var` reference in the name,
+    // including the `$`. A `$` that is not followed by a variable name is
+    // taken literally.
+    fn find_var(name: &str) -> Option<(usize, usize)> {
+        let mut from = 0;
+        while let Some(offset) = name[from..].find('$') {
+            let start = from + offset;
+            let ident = &name[start + 1..];
+            let len = ident
+                .find(|x: char| !(x.is_alphanumeric() || x == '_'))
+                .unwrap_or(ident.len());
+            if len > 0 {
+                return Some((start, start + 1 + len));
+            }
+            from = start + 1;
+        }
+        None
+    }
+
+    fn vars(&self) -> Vec<&str> {
+        let mut vars = vec![];
+        if !self.template {
+            return vars;
+        }
+        let mut rest = &*self.name;
+        while let Some((start, end)) = Self::find_var(rest) {
+            vars.push(&rest[start + 1..end]);
+            rest = &rest[end..];
+        }
+        vars
+    }
+
+    fn substitute(&self, bindings: &Bindings) -> Symbol {
+        let mut rest = &*self.name;
+        if !self.template {
+            return self.clone();
+        }
+
+        let mut name = String::new();
+        while let Some((start, end)) = Self::find_var(rest) {
+            name.push_str(&rest[..start]);
+            let var = &rest[start + 1..end];
+            // Family parameters are substituted before the read symbol is
+            // known, so variables without a binding are left for later.
+            match bindings.iter().find(|(name, _)| *name == var) {
+                Some((_, value)) => name.push_str(&value.name),
+                None => name.push_str(&rest[start..end]),
+            }
+            rest = &rest[end..];
+        }
+        name.push_str(rest);
+        Symbol::new(&name)
+    }
+}
+
+type Bindings<'a> = Vec<(&'a str, Symbol)>;
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Symbol(Symbol),
+    Set(Vec<Symbol>),
+    Bind(String, Vec<Symbol>),
+    // `*` matches any symbol, but only where no case with fewer wildcards
+    // matches.
+    Any,
+}
+
+impl Pattern {
+    fn matches(&self, symbol: &Symbol) -> bool {
+        match self {
+            Pattern::Symbol(expected) => expected == symbol,
+            Pattern::Set(set) | Pattern::Bind(_, set) => set.contains(symbol),
+            Pattern::Any => true,
+        }
+    }
+
+    fn bind<'a>(&'a self, symbol: &Symbol, bindings: &mut Bindings<'a>) {
+        if let Pattern::Bind(var, _) = self {
+            bindings.push((var, symbol.clone()));
+        }
+    }
+
+    fn substitute(&self, bindings: &Bindings) -> Pattern {
+        let substitute_all = |set: &[Symbol]| -> Vec<Symbol> {
+            let mut result: Vec<Symbol> = vec![];
+            for symbol in set {
+                let symbol = symbol.substitute(bindings);
+                if !result.contains(&symbol) {
+                    result.push(symbol);
+                }
+            }
+            result
+        };
+        match self {
+            Pattern::Symbol(symbol) => Pattern::Symbol(symbol.substitute(bindings)),
+            Pattern::Set(set) => Pattern::Set(substitute_all(set)),
+            Pattern::Bind(var, set) => Pattern::Bind(var.clone(), substitute_all(set)),
+            Pattern::Any => Pattern::Any,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 enum Step {
     Left,
     Right,
+    Up,
+    Down,
+    Stay,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum State {
+    Name(Symbol),
+    Tuple(Vec<State>),
+}
+
+impl State {
+    fn vars(&self) -> Vec<&str> {
+        match self {
+            State::Name(symbol) => symbol.vars(),
+            State::Tuple(components) => components.iter().flat_map(State::vars).collect(),
+        }
+    }
+
+    fn substitute(&self, bindings: &Bindings) -> State {
+        match self {
+            State::Name(symbol) => State::Name(symbol.substitute(bindings)),
+            State::Tuple(components) => State::Tuple(components.iter().map(|component| component.substitute(bindings)).collect()),
+        }
+    }
+
+    // Moves the state into the namespace of an import. Only the first
+    // component of a tuple gets the prefix, which is enough to keep the
+    // states of different files apart.
+    fn prefixed(&self, namespace: &str) -> State {
+        match self {
+            State::Name(symbol) => {
+                let name = format!("{namespace}::{name}", name = symbol.name);
+                State::Name(if symbol.template { Symbol::new(&name) } else { Symbol::literal(&name) })
+            }
+            State::Tuple(components) => {
+                let mut components = components.clone();
+                if let Some(first) = components.first_mut() {
+                    *first = first.prefixed(namespace);
+                }
+                State::Tuple(components)
+            }
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Members of a family are shown the way they are written, with
+            // the family and its arguments quoted separately where needed.
+            State::Name(symbol) => {
+                let family = symbol.name.strip_suffix(')').and_then(|rest| rest.split_once('('));
+                let Some((family, args)) = family.filter(|(family, _)| !family.is_empty()) else {
+                    return write!(f, "{symbol}");
+                };
+                write_symbol(f, family)?;
+                write!(f, "(")?;
+                for (i, arg) in args.split(", ").enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_symbol(f, arg)?;
+                }
+                write!(f, ")")
+            }
+            State::Tuple(components) => {
+                write!(f, "(")?;
+                for (i, component) in components.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{component}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Next {
+    State(State),
+    // Enters an imported program at `entry` and continues in `ret` once it
+    // reaches one of its `exits`.
+    Call {
+        entry: State,
+        exits: Rc<[State]>,
+        ret: State,
+    },
+}
+
+impl Next {
+    fn vars(&self) -> Vec<&str> {
+        match self {
+            Next::State(state) => state.vars(),
+            Next::Call{ret, ..} => ret.vars(),
+        }
+    }
+
+    fn substitute(&self, bindings: &Bindings) -> Next {
+        match self {
+            Next::State(state) => Next::State(state.substitute(bindings)),
+            Next::Call{entry, exits, ret} => Next::Call {
+                entry: entry.clone(),
+                exits: exits.clone(),
+                ret: ret.substitute(bindings),
+            },
+        }
+    }
+
+    fn prefixed(&self, namespace: &str) -> Next {
+        match self {
+            Next::State(state) => Next::State(state.prefixed(namespace)),
+            Next::Call{entry, exits, ret} => Next::Call {
+                entry: entry.prefixed(namespace),
+                exits: exits.iter().map(|exit| exit.prefixed(namespace)).collect(),
+                ret: ret.prefixed(namespace),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Next {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Next::State(state) => write!(f, "{state}"),
+            Next::Call{entry, ret, ..} => write!(f, "call {entry} {ret}"),
+        }
+    }
+}
+
+thread_local! {
+    // Every state a program can be in, numbered in the order it was first
+    // seen, so a running machine can keep its state as a number.
+    static STATES: RefCell<(HashMap<State, usize>, Vec<State>)> = RefCell::new((HashMap::new(), vec![]));
+}
+
+// A state by its number. The states of a program are numbered when it is
+// loaded, and states built from `$vars` while running as they come up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StateId(usize);
+
+impl StateId {
+    fn of(state: &State) -> StateId {
+        STATES.with(|states| {
+            let (ids, names) = &mut *states.borrow_mut();
+            if let Some(&id) = ids.get(state) {
+                return StateId(id);
+            }
+            let id = names.len();
+            ids.insert(state.clone(), id);
+            names.push(state.clone());
+            StateId(id)
+        })
+    }
+}
+
+impl fmt::Display for StateId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        STATES.with(|states| write!(f, "{state}", state = states.borrow().1[self.0]))
+    }
+}
+
+// Where a case leads, with the states numbered.
+#[derive(Debug, Clone)]
+enum Jump {
+    State(StateId),
+    Call {
+        entry: StateId,
+        exits: Rc<[StateId]>,
+        ret: StateId,
+    },
+}
+
+impl Jump {
+    fn of(next: &Next) -> Jump {
+        match next {
+            Next::State(state) => Jump::State(StateId::of(state)),
+            Next::Call{entry, exits, ret} => Jump::Call {
+                entry: StateId::of(entry),
+                exits: exits.iter().map(StateId::of).collect(),
+                ret: StateId::of(ret),
+            },
+        }
+    }
+}
+
+// What a case does to the tapes, each op naming the tape it works on.
+#[derive(Debug, Clone)]
+enum Op {
+    Write(usize, Symbol),
+    // Writes the symbol the tape is filled with.
+    Erase(usize),
+    Move(usize, Step),
+}
+
+impl Op {
+    fn substitute(&self, bindings: &Bindings) -> Op {
+        match self {
+            Op::Write(tape, symbol) => Op::Write(*tape, symbol.substitute(bindings)),
+            op => op.clone(),
+        }
+    }
+}
+
+// A case reads once per tape, then runs its ops. Single-tape cases are
+// written without the parentheses.
+#[derive(Debug, Clone)]
+struct Case {
+    state: State,
+    read: Vec<Pattern>,
+    ops: Vec<Op>,
+    next: Next,
+    // Relative chance of taking this case when several match and the run
+    // is seeded. Written `@0.3` after the next state; 1 by default.
+    weight: f64,
+}
+
+impl Case {
+    fn substitute(&self, bindings: &Bindings) -> Case {
+        Case {
+            state: self.state.substitute(bindings),
+            read: self.read.iter().map(|pattern| pattern.substitute(bindings)).collect(),
+            ops: self.ops.iter().map(|op| op.substitute(bindings)).collect(),
+            next: self.next.substitute(bindings),
+            weight: self.weight,
+        }
+    }
+
+    fn wildcards(&self) -> usize {
+        self.read.iter().filter(|pattern| matches!(pattern, Pattern::Any)).count()
+    }
+}
+
+// A parameter of a state family such as `Carry(d in Digit)`.
+#[derive(Debug)]
+struct Param {
+    name: String,
+    set: Vec<Symbol>,
+}
+
+// A case whose state is a family. It stands for one concrete case per
+// combination of parameter values, with each `$param` replaced by its value.
+#[derive(Debug)]
+struct Template {
+    params: Vec<Param>,
+    case: Case,
+}
+
+// Calls `f` once for every combination of parameter values.
+fn for_each_binding<'a>(params: &'a [Param], bindings: &mut Bindings<'a>, f: &mut impl FnMut(&Bindings)) {
+    let Some(param) = params.get(bindings.len()) else {
+        f(bindings);
+        return;
+    };
+    for value in &param.set {
+        bindings.push((&param.name, value.clone()));
+        for_each_binding(params, bindings, f);
+        bindings.pop();
+    }
+}
+
+impl Template {
+    fn expand(&self, cases: &mut Vec<Case>) {
+        for_each_binding(&self.params, &mut vec![], &mut |bindings| {
+            cases.push(self.case.substitute(bindings));
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    // No case matched and the program declares no halting states.
+    Halted,
+    Accepted,
+    Rejected,
+    // A limit given on the command line ended the run first.
+    Stopped,
+    // The machine came back to a configuration it had already been in.
+    Looping,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+struct Frame {
+    exits: Rc<[StateId]>,
+    ret: StateId,
+}
+
+// Cells are kept in chunks of this many, and only chunks that hold something
+// other than the default symbol are kept at all, so a long trip over blank
+// tape takes no memory.
+const CHUNK_SIZE: isize = 64;
+
+// A tape is a grid of rows; ordinary tapes have a single row and never move
+// up or down. Positions are relative to the first cell of the tape file and
+// go negative when the head runs off to the left or the top.
+#[derive(Debug, Clone)]
+struct Tape {
+    // Keyed by row and the position of the chunk within the row.
+    chunks: BTreeMap<(isize, isize), Vec<Symbol>>,
+    default: Symbol,
+    x: isize,
+    y: isize,
+    // The columns and rows the tape file and the head have covered.
+    columns: (isize, isize),
+    rows: (isize, isize),
+}
+
+// Tapes are compared by contents and head alone: where the head has been
+// does not change what the machine does next.
+impl PartialEq for Tape {
+    fn eq(&self, other: &Self) -> bool {
+        (self.x, self.y, &self.default, &self.chunks) == (other.x, other.y, &other.default, &other.chunks)
+    }
+}
+
+impl Eq for Tape {}
+
+impl Hash for Tape {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.x, self.y, &self.default, &self.chunks).hash(state);
+    }
+}
+
+impl Tape {
+    // The last symbol of a tape is what the tape is filled with when the
+    // head runs past either end.
+    fn new(rows: &[Vec<Cells>]) -> Option<Tape> {
+        if rows.iter().any(|row| Cells::len(row) == 0) {
+            return None;
+        }
+        let default = Cells::last(rows.last()?)?.clone();
+        let width = rows.iter().map(|row| Cells::len(row)).max()? as isize;
+        let height = rows.len() as isize;
+        let mut tape = Tape{
+            chunks: BTreeMap::new(),
+            default,
+            x: 0,
+            y: 0,
+            columns: (0, width - 1),
+            rows: (0, height - 1),
+        };
+        for (y, row) in rows.iter().enumerate() {
+            Cells::store(row, &mut tape, &mut 0, y as isize);
+        }
+        Some(tape)
+    }
+
+    fn locate(x: isize, y: isize) -> ((isize, isize), usize) {
+        ((y, x.div_euclid(CHUNK_SIZE)), x.rem_euclid(CHUNK_SIZE) as usize)
+    }
+
+    fn get(&self, x: isize, y: isize) -> &Symbol {
+        let (key, i) = Self::locate(x, y);
+        self.chunks.get(&key).map_or(&self.default, |chunk| &chunk[i])
+    }
+
+    fn set(&mut self, x: isize, y: isize, symbol: Symbol) {
+        let (key, i) = Self::locate(x, y);
+        let default = &self.default;
+        if symbol != *default {
+            self.chunks.entry(key).or_insert_with(|| vec![default.clone(); CHUNK_SIZE as usize])[i] = symbol;
+        } else if let Some(chunk) = self.chunks.get_mut(&key) {
+            chunk[i] = symbol;
+            // Dropping blank chunks also keeps equal tapes equal.
+            if chunk.iter().all(|cell| cell == default) {
+                self.chunks.remove(&key);
+            }
+        }
+    }
+
+    fn read(&self) -> &Symbol {
+        self.get(self.x, self.y)
+    }
+
+    fn write(&mut self, symbol: Symbol) {
+        self.set(self.x, self.y, symbol);
+    }
+
+    fn step(&mut self, step: &Step) {
+        match step {
+            Step::Left => self.x -= 1,
+            Step::Right => self.x += 1,
+            Step::Up => self.y -= 1,
+            Step::Down => self.y += 1,
+            Step::Stay => {}
+        }
+        self.columns = (self.columns.0.min(self.x), self.columns.1.max(self.x));
+        self.rows = (self.rows.0.min(self.y), self.rows.1.max(self.y));
+    }
+
+    // The number of cells the tape file and the head have covered.
+    fn size(&self) -> usize {
+        let (left, right) = self.columns;
+        let (top, bottom) = self.rows;
+        ((right - left + 1) * (bottom - top + 1)) as usize
+    }
+
+    fn is_grid(&self) -> bool {
+        self.rows.0 != self.rows.1
+    }
+
+    fn print(&self, prefix: &str) {
+        let indent = " ".repeat(prefix.len());
+        for y in self.rows.0..=self.rows.1 {
+            let mut buffer = String::new();
+
+            buffer.push_str(if y == self.rows.0 { prefix } else { &indent });
+            let mut blank = 0;
+            let mut x = self.columns.0;
+            while x <= self.columns.1 {
+                if (x, y) == (self.x, self.y) {
+                    self.print_blank(&mut buffer, blank);
+                    blank = 0;
+                    let _ = write!(&mut buffer, "[{symbol}] ", symbol = self.get(x, y));
+                    x += 1;
+                    continue;
+                }
+                let (key, i) = Self::locate(x, y);
+                if let Some(chunk) = self.chunks.get(&key) {
+                    if chunk[i] == self.default {
+                        blank += 1;
+                    } else {
+                        self.print_blank(&mut buffer, blank);
+                        blank = 0;
+                        let _ = write!(&mut buffer, "{symbol} ", symbol = chunk[i]);
+                    }
+                    x += 1;
+                    continue;
+                }
+
+                // Skip straight to the next stored chunk, the head or the
+                // end of the row, whichever comes first.
+                let last = (y, self.columns.1.div_euclid(CHUNK_SIZE));
+                let mut next = self.chunks.range(key..=last).next().map_or(self.columns.1 + 1, |(&(_, chunk), _)| chunk * CHUNK_SIZE);
+                if y == self.y && self.x > x {
+                    next = next.min(self.x);
+                }
+                let next = next.min(self.columns.1 + 1);
+                blank += (next - x) as usize;
+                x = next;
+            }
+            self.print_blank(&mut buffer, blank);
+            if y == self.y {
+                if self.is_grid() {
+                    let _ = write!(&mut buffer, "@{x},{y}", x = self.x);
+                } else {
+                    let _ = write!(&mut buffer, "@{x}", x = self.x);
+                }
+            }
+            println!("{buffer}");
+        }
+    }
+
+    // Long stretches of the default symbol are shown with the `x*n` notation
+    // of tape files.
+    fn print_blank(&self, buffer: &mut String, count: usize) {
+        if count > 8 {
+            let _ = write!(buffer, "{default}*{count} ", default = self.default);
+        } else {
+            for _ in 0..count {
+                let _ = write!(buffer, "{default} ", default = self.default);
+            }
+        }
+    }
+
+    fn print_visited(&self, prefix: &str) {
+        let (left, right) = self.columns;
+        if self.is_grid() {
+            let (top, bottom) = self.rows;
+            println!("VISITED: {prefix}columns {left} to {right}, rows {top} to {bottom}");
+        } else {
+            println!("VISITED: {prefix}cells {left} to {right}");
+        }
+    }
+}
+
+// SplitMix64. Plenty for choosing between cases, and the same seed always
+// gives the same run.
+#[derive(Debug)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self{state: seed}
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+struct Machine {
+    state: StateId,
+    tapes: Vec<Tape>,
+    halt: bool,
+    stack: Vec<Frame>,
+}
+
+impl Machine {
+    fn reads(&self, case: &Case) -> bool {
+        case.read.iter().zip(&self.tapes).all(|(pattern, tape)| pattern.matches(tape.read()))
+    }
+
+    // `next` is where the case leads if that was worked out on loading,
+    // which it is unless it depends on `$vars`.
+    fn apply(&mut self, case: &Case, next: Option<&Jump>) {
+        let mut bindings = vec![];
+        for (pattern, tape) in case.read.iter().zip(&self.tapes) {
+            pattern.bind(tape.read(), &mut bindings);
+        }
+        for op in &case.ops {
+            match op {
+                Op::Write(i, symbol) => self.tapes[*i].write(symbol.substitute(&bindings)),
+                Op::Erase(i) => {
+                    let tape = &mut self.tapes[*i];
+                    tape.write(tape.default.clone());
+                }
+                Op::Move(i, step) => self.tapes[*i].step(step),
+            }
+        }
+        let substituted;
+        let next = match next {
+            Some(next) => next,
+            None => {
+                substituted = Jump::of(&case.next.substitute(&bindings));
+                &substituted
+            }
+        };
+        match next {
+            Jump::State(state) => self.state = *state,
+            Jump::Call{entry, exits, ret} => {
+                self.stack.push(Frame{exits: exits.clone(), ret: *ret});
+                self.state = *entry;
+            }
+        }
+        self.return_from_calls();
+    }
+
+    fn next(&mut self, cases: &[Case], table: &mut Table) {
+        let candidates = table.candidates(self, cases);
+        if let Some(&i) = table.cases[candidates].first() {
+            self.apply(&cases[i], table.jumps[i].as_ref());
+            self.halt = false;
+        }
+    }
+
+    // Picks one of the matching cases at random, according to their weights.
+    fn next_random(&mut self, cases: &[Case], table: &mut Table, rng: &mut Rng) {
+        let candidates = table.candidates(self, cases);
+        let candidates = &table.cases[candidates];
+        let total: f64 = candidates.iter().map(|&i| cases[i].weight).sum();
+        let mut choice = rng.next_f64() * total;
+        for &i in candidates {
+            if choice < cases[i].weight {
+                self.apply(&cases[i], table.jumps[i].as_ref());
+                self.halt = false;
+                return;
+            }
+            choice -= cases[i].weight;
+        }
+        // Rounding can leave a sliver past the last weight.
+        if let Some(&i) = candidates.last() {
+            self.apply(&cases[i], table.jumps[i].as_ref());
+            self.halt = false;
+        }
+    }
+
+    fn return_from_calls(&mut self) {
+        while let Some(frame) = self.stack.last() {
+            if !frame.exits.contains(&self.state) {
+                break;
+            }
+            self.state = frame.ret;
+            self.stack.pop();
+        }
+    }
+
+    // Symbols under the heads, as a single symbol or a tuple `(a b)`.
+    fn reading(&self) -> String {
+        let symbols: Vec<String> = self.tapes.iter().map(|tape| tape.read().to_string()).collect();
+        if let [symbol] = &symbols[..] {
+            symbol.clone()
+        } else {
+            format!("({symbols})", symbols = symbols.join(" "))
+        }
+    }
+
+    fn print(&self) {
+        let prefix = format!("{state}: ", state = self.state);
+        let indent = " ".repeat(prefix.len());
+        for (i, tape) in self.tapes.iter().enumerate() {
+            tape.print(if i == 0 { &prefix } else { &indent });
+        }
+    }
+
+    fn print_visited(&self) {
+        if let [tape] = &self.tapes[..] {
+            tape.print_visited("");
+            return;
+        }
+        for (i, tape) in self.tapes.iter().enumerate() {
+            tape.print_visited(&format!("tape {n} ", n = i + 1));
+        }
+    }
+}
+
+// The program compiled for running: states are numbered, and which cases
+// match is kept by state and the symbols under the heads. A row is filled in
+// the first time the machine is in that situation, so after that a step is
+// two index lookups. Single-tape rows are indexed by the interned symbol;
+// the symbols under several heads are numbered together.
+#[derive(Debug)]
+struct Table {
+    // The cases of each state, and the jump of each case that does not
+    // depend on `$vars`.
+    by_state: Vec<Vec<usize>>,
+    jumps: Vec<Option<Jump>>,
+    outcomes: Vec<Option<Outcome>>,
+    readings: HashMap<Vec<u32>, usize>,
+    reading: Vec<u32>,
+    // Ranges into `cases`, which holds the matching cases of every row
+    // filled so far back to back.
+    rows: Vec<Vec<Option<(usize, usize)>>>,
+    cases: Vec<usize>,
+}
+
+impl Table {
+    fn new(program: &Program) -> Table {
+        let mut by_state = vec![];
+        for (i, case) in program.cases.iter().enumerate() {
+            let state = StateId::of(&case.state);
+            if by_state.len() <= state.0 {
+                by_state.resize_with(state.0 + 1, Vec::new);
+            }
+            by_state[state.0].push(i);
+        }
+        let jumps = program.cases.iter().map(|case| {
+            if case.next.vars().is_empty() { Some(Jump::of(&case.next)) } else { None }
+        }).collect();
+        let mut outcomes = vec![];
+        for (state, outcome) in &program.halts {
+            let state = StateId::of(state);
+            if outcomes.len() <= state.0 {
+                outcomes.resize(state.0 + 1, None);
+            }
+            outcomes[state.0] = Some(*outcome);
+        }
+        Table{by_state, jumps, outcomes, readings: HashMap::new(), reading: vec![], rows: vec![], cases: vec![]}
+    }
+
+    fn outcome(&self, state: StateId) -> Option<Outcome> {
+        self.outcomes.get(state.0).copied().flatten()
+    }
+
+    // The matching cases that use the fewest wildcards, in program order, as
+    // a range of `self.cases`.
+    fn candidates(&mut self, machine: &Machine, cases: &[Case]) -> Range<usize> {
+        let reading = if let [tape] = &machine.tapes[..] {
+            tape.read().id as usize
+        } else {
+            self.reading.clear();
+            self.reading.extend(machine.tapes.iter().map(|tape| tape.read().id));
+            if let Some(&reading) = self.readings.get(&self.reading[..]) {
+                reading
+            } else {
+                let reading = self.readings.len();
+                self.readings.insert(self.reading.clone(), reading);
+                reading
+            }
+        };
+        let state = machine.state.0;
+        if self.rows.len() <= state {
+            self.rows.resize_with(state + 1, Vec::new);
+        }
+        let row = &mut self.rows[state];
+        if row.len() <= reading {
+            row.resize(reading + 1, None);
+        }
+        if let Some((start, end)) = row[reading] {
+            return start..end;
+        }
+
+        let matching: Vec<usize> = self.by_state.get(state).into_iter().flatten().copied()
+            .filter(|&i| machine.reads(&cases[i]))
+            .collect();
+        let fewest = matching.iter().map(|&i| cases[i].wildcards()).min();
+        let start = self.cases.len();
+        self.cases.extend(matching.into_iter().filter(|&i| Some(cases[i].wildcards()) == fewest));
+        let end = self.cases.len();
+        self.rows[state][reading] = Some((start, end));
+        start..end
+    }
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    failed: bool,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Self{source, failed: false}
+    }
+
+    // Reports problems that the lexer could only note while producing
+    // tokens.
+    fn finish(&self) -> Result<()> {
+        if self.failed {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    // Skips whitespace, `#` and `//` line comments, and `/* */` block
+    // comments, which may nest.
+    fn skip_whitespace_and_comments(&mut self) {
+        loop {
+            self.source = self.source.trim_start();
+            if is_line_comment(self.source) {
+                let end = self.source.find('\n').unwrap_or(self.source.len());
+                self.source = &self.source[end..];
+            } else if self.source.starts_with("/*") {
+                let mut depth = 0;
+                let mut rest = self.source;
+                loop {
+                    if rest.starts_with("/*") {
+                        depth += 1;
+                        rest = &rest[2..];
+                    } else if rest.starts_with("*/") {
+                        depth -= 1;
+                        rest = &rest[2..];
+                        if depth == 0 {
+                            break;
+                        }
+                    } else if let Some(x) = rest.chars().next() {
+                        rest = &rest[x.len_utf8()..];
+                    } else {
+                        eprintln!("ERROR: unclosed block comment");
+                        self.failed = true;
+                        break;
+                    }
+                }
+                self.source = rest;
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+fn is_punct(x: char) -> bool {
+    matches!(x, '{' | '}' | '(' | ')' | ',')
+}
+
+fn is_line_comment(source: &str) -> bool {
+    source.starts_with('#') || source.starts_with("//")
+}
+
+fn is_comment(source: &str) -> bool {
+    is_line_comment(source) || source.starts_with("/*")
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.skip_whitespace_and_comments();
+        let x = self.source.chars().next()?;
+        let end = if is_punct(x) {
+            x.len_utf8()
+        } else if x == '"' {
+            let mut escaped = false;
+            let close = self.source.char_indices().skip(1).find(|&(_, x)| {
+                let close = !escaped && x == '"';
+                escaped = !escaped && x == '\\';
+                close
+            });
+            if let Some((i, _)) = close {
+                i + 1
+            } else {
+                eprintln!("ERROR: unclosed string");
+                self.failed = true;
+                self.source.len()
+            }
+        } else {
+            self.source
+                .char_indices()
+                .find(|&(i, x)| x.is_whitespace() || is_punct(x) || x == '"' || is_comment(&self.source[i..]))
+                .map_or(self.source.len(), |(i, _)| i)
+        };
+        let (token, rest) = self.source.split_at(end);
+        self.source = rest;
+        Some(token)
+    }
+}
+
+// Strips the quotes off a string token and resolves the `\"`, `\\`, `\n`
+// and `\t` escapes.
+fn unquote(token: &str) -> Result<String> {
+    // An unclosed string is the rest of the input, which may still end in an
+    // escaped quote. The lexer has already reported it either way.
+    if token.len() < 2 || !token.ends_with('"') {
+        return Err(());
+    }
+
+    let mut result = String::new();
+    let mut chars = token[1..token.len() - 1].chars();
+    while let Some(x) = chars.next() {
+        if x != '\\' {
+            result.push(x);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => result.push('"'),
+            Some('\\') => result.push('\\'),
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some(x) => {
+                eprintln!("ERROR: unknown escape \\{x} in {token}");
+                return Err(());
+            }
+            // The closing quote was escaped, so the string never closed.
+            None => return Err(()),
+        }
+    }
+    Ok(result)
+}
+
+// Quoted tokens are always symbols, even when they read like syntax.
+fn symbol_from_token(token: &str) -> Result<Symbol> {
+    if token.starts_with('"') {
+        Ok(Symbol::literal(&unquote(token)?))
+    } else {
+        Ok(Symbol::new(token))
+    }
+}
+
+fn parse_symbol<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<Symbol> {
+    if let Some(token) = lexer.next() {
+        symbol_from_token(token)
+    } else {
+        eprintln!("ERROR: expected symbol but reached end of input");
+        Err(())
+    }
+}
+
+fn parse_string<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<String> {
+    match lexer.next() {
+        Some(token) if token.starts_with('"') => unquote(token),
+        Some(token) => {
+            eprintln!("ERROR: expected string but got {token}");
+            Err(())
+        }
+        None => {
+            eprintln!("ERROR: expected string but reached end of input");
+            Err(())
+        }
+    }
+}
+
+fn parse_step<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<Step> {
+    match lexer.next() {
+        Some("->") => Ok(Step::Right),
+        Some("<-") => Ok(Step::Left),
+        Some(".") => Ok(Step::Stay),
+        Some("^") => Ok(Step::Up),
+        Some("v") => Ok(Step::Down),
+        Some(token) => {
+            eprintln!("ERROR: expected '->', '<-', '^', 'v' or '.' but got {token}");
+            Err(())
+        }
+        None => {
+            eprintln!("ERROR: expected step but reached end of input");
+            Err(())
+        }
+    }
+}
+
+type Sets = HashMap<String, Vec<Symbol>>;
+
+#[derive(Debug, Default)]
+struct Program {
+    sets: Sets,
+    cases: Vec<Case>,
+    halts: Vec<(State, Outcome)>,
+    start: Option<State>,
+    modules: Vec<Module>,
+    // Set by the `grid` directive: the tape is a single two-dimensional grid
+    // whose rows are the `|`-separated sections of the tape file.
+    grid: bool,
+}
+
+// A program brought in with `import`, as seen from the importing program.
+#[derive(Debug)]
+struct Module {
+    namespace: String,
+    start: Option<State>,
+    exits: Rc<[State]>,
+}
+
+impl Program {
+    fn mentions(&self, state: &State) -> bool {
+        self.cases.iter().any(|case| case.state == *state) || self.outcome(state).is_some()
+    }
+
+    fn outcome(&self, state: &State) -> Option<Outcome> {
+        self.halts.iter().find(|(halt, _)| halt == state).map(|(_, outcome)| *outcome)
+    }
+}
+
+fn parse_set_literal<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<Vec<Symbol>> {
+    let mut set = vec![];
+    loop {
+        match lexer.next() {
+            Some("}") => break,
+            Some("{") => {
+                eprintln!("ERROR: nested sets are not allowed");
+                return Err(());
+            }
+            Some(token) => {
+                let symbol = symbol_from_token(token)?;
+                if !set.contains(&symbol) {
+                    set.push(symbol);
+                }
+            }
+            None => {
+                eprintln!("ERROR: expected '}}' but reached end of input");
+                return Err(());
+            }
+        }
+    }
+    Ok(set)
+}
+
+fn lookup_set(name: &str, sets: &Sets) -> Result<Vec<Symbol>> {
+    if let Some(set) = sets.get(name) {
+        Ok(set.clone())
+    } else {
+        eprintln!("ERROR: unknown set {name}");
+        Err(())
+    }
+}
+
+fn parse_set_primary<'a>(lexer: &mut impl Iterator<Item = &'a str>, sets: &Sets) -> Result<Vec<Symbol>> {
+    match lexer.next() {
+        Some("{") => parse_set_literal(lexer),
+        Some(name) => lookup_set(name, sets),
+        None => {
+            eprintln!("ERROR: expected set but reached end of input");
+            Err(())
+        }
+    }
+}
+
+// Operators are applied left to right with equal precedence, so
+// `A | B \ C` means `(A | B) \ C`.
+fn parse_set_expr<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, sets: &Sets) -> Result<Vec<Symbol>> {
+    let lhs = parse_set_primary(lexer, sets)?;
+    parse_set_ops(lexer, sets, lhs)
+}
+
+fn parse_set_ops<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, sets: &Sets, mut result: Vec<Symbol>) -> Result<Vec<Symbol>> {
+    while let Some(op) = lexer.next_if(|token| matches!(*token, "|" | "&" | "\\")) {
+        let rhs = parse_set_primary(lexer, sets)?;
+        // Operators are applied while parsing, before any parameter has a
+        // value, so they could only give wrong answers for `$param`.
+        if let Some(symbol) = result.iter().chain(&rhs).find(|symbol| !symbol.vars().is_empty()) {
+            eprintln!("ERROR: {name} cannot be used in a set operation", name = symbol.name);
+            return Err(());
+        }
+        match op {
+            "|" => {
+                for symbol in rhs {
+                    if !result.contains(&symbol) {
+                        result.push(symbol);
+                    }
+                }
+            }
+            "&" => result.retain(|symbol| rhs.contains(symbol)),
+            "\\" => result.retain(|symbol| !rhs.contains(symbol)),
+            _ => unreachable!(),
+        }
+    }
+    Ok(result)
+}
+
+// A binding is written `$var:Set`, where `Set` is any set expression. The
+// lexer keeps `$var:Name` together as one token, so the first operand may
+// arrive glued to the variable.
+fn parse_binding<'a>(binding: &str, lexer: &mut Peekable<impl Iterator<Item = &'a str>>, sets: &Sets) -> Result<Pattern> {
+    let Some((var, first)) = binding[1..].split_once(':') else {
+        eprintln!("ERROR: variable {binding} must be bound to a set, e.g. {binding}:Digit");
+        return Err(());
+    };
+    if var.is_empty() || var.contains(|x: char| !(x.is_alphanumeric() || x == '_')) {
+        eprintln!("ERROR: invalid variable name in {binding}");
+        return Err(());
+    }
+    let lhs = if first.is_empty() {
+        parse_set_primary(lexer, sets)?
+    } else {
+        lookup_set(first, sets)?
+    };
+    let set = parse_set_ops(lexer, sets, lhs)?;
+    Ok(Pattern::Bind(var.to_string(), set))
+}
+
+fn parse_pattern<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, sets: &Sets, params: &[Param]) -> Result<Pattern> {
+    match lexer.peek() {
+        Some(&token) if token == "{" || sets.contains_key(token) => {
+            Ok(Pattern::Set(parse_set_expr(lexer, sets)?))
+        }
+        Some(&token) if token.starts_with('$') && !params.iter().any(|param| param.name == token[1..]) => {
+            lexer.next();
+            let pattern = parse_binding(token, lexer, sets)?;
+            if let Pattern::Bind(var, _) = &pattern {
+                if params.iter().any(|param| &param.name == var) {
+                    eprintln!("ERROR: variable ${var} is already a parameter of the state");
+                    return Err(());
+                }
+            }
+            Ok(pattern)
+        }
+        Some(&"*") => {
+            lexer.next();
+            Ok(Pattern::Any)
+        }
+        _ => Ok(Pattern::Symbol(parse_symbol(lexer)?)),
+    }
+}
+
+// `*` in the write slot keeps whatever the tape holds.
+fn parse_write<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>) -> Result<Option<Symbol>> {
+    if lexer.next_if_eq(&"*").is_some() {
+        Ok(None)
+    } else {
+        parse_symbol(lexer).map(Some)
+    }
+}
+
+fn check_vars(template: &str, vars: Vec<&str>, read: &[Pattern], params: &[Param]) -> Result<()> {
+    for var in vars {
+        if params.iter().any(|param| param.name == var) {
+            continue;
+        }
+        if !read.iter().any(|pattern| matches!(pattern, Pattern::Bind(bound, _) if bound == var)) {
+            eprintln!("ERROR: variable ${var} in {template} is not bound by the read pattern or the state");
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+// Tokens are slices of the same source, so two tokens are adjacent when one
+// ends where the other starts. This is what tells the family `Carry(d)`
+// apart from the state `Carry` followed by a tuple.
+fn adjacent(left: &str, right: &str) -> bool {
+    left.as_bytes().as_ptr_range().end == right.as_ptr()
+}
+
+fn expect_token<'a>(lexer: &mut impl Iterator<Item = &'a str>, expected: &str) -> Result<()> {
+    match lexer.next() {
+        Some(token) if token == expected => Ok(()),
+        Some(token) => {
+            eprintln!("ERROR: expected '{expected}' but got {token}");
+            Err(())
+        }
+        None => {
+            eprintln!("ERROR: expected '{expected}' but reached end of input");
+            Err(())
+        }
+    }
+}
+
+// Members of a state family are named `Name(a, b)`, however the arguments
+// were spaced in the source.
+fn family_name(name: &str, args: &[String]) -> Symbol {
+    Symbol::new(&format!("{name}({args})", args = args.join(", ")))
+}
+
+fn parse_arg<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<&'a str> {
+    match lexer.next() {
+        Some(token) if token.len() == 1 && is_punct(token.chars().next().unwrap()) => {
+            eprintln!("ERROR: expected state name but got {token}");
+            Err(())
+        }
+        Some(token) => Ok(token),
+        None => {
+            eprintln!("ERROR: expected state name but reached end of input");
+            Err(())
+        }
+    }
+}
+
+// Consumes the separator after a family argument or tuple component and
+// reports whether it closed the list.
+fn parse_arg_separator<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<bool> {
+    match lexer.next() {
+        Some(",") => Ok(false),
+        Some(")") => Ok(true),
+        Some(token) => {
+            eprintln!("ERROR: expected ',' or ')' but got {token}");
+            Err(())
+        }
+        None => {
+            eprintln!("ERROR: expected ')' but reached end of input");
+            Err(())
+        }
+    }
 }
 
-#[derive(Debug)]
-struct Case<'a> {
-    state: Symbol<'a>,
-    read: Symbol<'a>,
-    write: Symbol<'a>,
-    step: Step,
-    next: Symbol<'a>,
+fn declare_param<'a>(name: &str, lexer: &mut Peekable<impl Iterator<Item = &'a str>>, sets: &Sets, params: &mut Vec<Param>) -> Result<Symbol> {
+    if name.is_empty() || name.contains(|x: char| !(x.is_alphanumeric() || x == '_')) {
+        eprintln!("ERROR: invalid parameter name {name}");
+        return Err(());
+    }
+    if params.iter().any(|param| param.name == name) {
+        eprintln!("ERROR: parameter {name} is declared twice");
+        return Err(());
+    }
+    let set = parse_set_expr(lexer, sets)?;
+    params.push(Param{name: name.to_string(), set});
+    Ok(Symbol::new(&format!("${name}")))
 }
 
-#[derive(Debug)]
-struct Machine<'a> {
-    state: Symbol<'a>,
-    tape: Vec<Symbol<'a>>,
-    tape_default: Symbol<'a>,
-    head: usize,
-    halt: bool,
+// The state of a case: a plain name, a family `Name(p in Set, ...)` or a
+// tuple `(A, p in Set, ...)`. Arguments and components that are not
+// `p in Set` stay fixed, so `Cmp(d in Digit, 0)` is also accepted.
+fn parse_state<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, sets: &Sets, params: &mut Vec<Param>) -> Result<State> {
+    if lexer.next_if_eq(&"(").is_some() {
+        let mut components = vec![];
+        loop {
+            let component = parse_state(lexer, sets, params)?;
+            if lexer.next_if_eq(&"in").is_some() {
+                let State::Name(name) = component else {
+                    eprintln!("ERROR: expected parameter name before 'in' but got {component}");
+                    return Err(());
+                };
+                components.push(State::Name(declare_param(&name.name, lexer, sets, params)?));
+            } else {
+                components.push(component);
+            }
+            if parse_arg_separator(lexer)? {
+                break;
+            }
+        }
+        return Ok(State::Tuple(components));
+    }
+
+    let name = parse_arg(lexer)?;
+    if lexer.next_if(|token| *token == "(" && adjacent(name, token)).is_none() {
+        return Ok(State::Name(symbol_from_token(name)?));
+    }
+
+    let mut args = vec![];
+    loop {
+        let arg = parse_arg(lexer)?;
+        if lexer.next_if_eq(&"in").is_some() {
+            args.push(declare_param(arg, lexer, sets, params)?.name.to_string());
+        } else {
+            args.push(symbol_from_token(arg)?.name.to_string());
+        }
+        if parse_arg_separator(lexer)? {
+            break;
+        }
+    }
+    Ok(State::Name(family_name(&symbol_from_token(name)?.name, &args)))
 }
 
-impl<'a> Machine<'a> {
-    fn next(&mut self, cases: &[Case<'a>]) -> Result<()> {
-        for case in cases {
-            if case.state == self.state && case.read == self.tape[self.head] {
-                self.tape[self.head].name = case.write.name;
-                match case.step {
-                    Step::Left => {
-                        if self.head == 0 {
-                            eprintln!("ERROR: tape underflow.");
-                            return Err(());
-                        }
-                        self.head -= 1;
-                    }
-                    Step::Right => {
-                        self.head += 1;
-                    }
-                }
-                self.state.name = case.next.name;
-                self.halt = false;
+// A reference to a state, such as the next state of a case: a plain name, a
+// family member `Name(a, ...)` or a tuple `(A, ...)`.
+fn parse_state_ref<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>) -> Result<State> {
+    if lexer.next_if_eq(&"(").is_some() {
+        let mut components = vec![];
+        loop {
+            components.push(parse_state_ref(lexer)?);
+            if parse_arg_separator(lexer)? {
                 break;
             }
         }
-        Ok(())
+        return Ok(State::Tuple(components));
     }
 
-    fn print(&self) {
-        let mut buffer = String::new();
-        let mut head = 0;
+    let name = parse_arg(lexer)?;
+    if lexer.next_if(|token| *token == "(" && adjacent(name, token)).is_none() {
+        return Ok(State::Name(symbol_from_token(name)?));
+    }
 
-        let _ = write!(&mut buffer, "{state}: ", state = self.state.name);
-        for (i, symbol) in self.tape.iter().enumerate() {
-            if i == self.head {
-                head = buffer.len();
+    let mut args = vec![];
+    loop {
+        args.push(symbol_from_token(parse_arg(lexer)?)?.name.to_string());
+        if parse_arg_separator(lexer)? {
+            break;
+        }
+    }
+    Ok(State::Name(family_name(&symbol_from_token(name)?.name, &args)))
+}
+
+// `call ns Return` enters the program imported as `ns` at its start state.
+// Any of its halting states then leads back to `Return`.
+fn parse_call<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, program: &Program) -> Result<Next> {
+    let namespace = parse_symbol(lexer)?.name;
+    let Some(module) = program.modules.iter().find(|module| *module.namespace == *namespace) else {
+        eprintln!("ERROR: call to unknown namespace {namespace}");
+        return Err(());
+    };
+    let Some(entry) = &module.start else {
+        eprintln!("ERROR: {namespace} has no start state to call");
+        return Err(());
+    };
+    if module.exits.is_empty() {
+        eprintln!("ERROR: {namespace} has no halting states to return from");
+        return Err(());
+    }
+    let ret = parse_state_ref(lexer)?;
+    Ok(Next::Call{entry: entry.clone(), exits: module.exits.clone(), ret})
+}
+
+// Parses one item per tape: either a single item, or a tuple `(a b ...)` of
+// `count` items when the tape count is already known.
+fn parse_per_tape<'a, L, T>(lexer: &mut Peekable<L>, count: Option<usize>, mut parse_item: impl FnMut(&mut Peekable<L>) -> Result<T>) -> Result<Vec<T>>
+where
+    L: Iterator<Item = &'a str>,
+{
+    let items = if lexer.next_if_eq(&"(").is_some() {
+        let mut items = vec![];
+        while lexer.next_if_eq(&")").is_none() {
+            if lexer.peek().is_none() {
+                eprintln!("ERROR: expected ')' but reached end of input");
+                return Err(());
             }
-            let _ = write!(&mut buffer, "{name} ", name = symbol.name);
+            items.push(parse_item(lexer)?);
         }
-        println!("{buffer}");
-        // TODO: use the field width formatting of println
-        for _ in 0..head {
-            print!(" ");
+        items
+    } else {
+        vec![parse_item(lexer)?]
+    };
+    if let Some(count) = count {
+        if items.len() != count {
+            eprintln!("ERROR: expected {count} items, one per tape, but got {len}", len = items.len());
+            return Err(());
+        }
+    }
+    Ok(items)
+}
+
+// Turing's notation for what a case does: `P<symbol>` prints, `E` erases,
+// and `L`, `R`, `U`, `D` move the head. The list ends at `->`, which leads
+// to the next state. Quoted symbols are printed with `P"symbol"`.
+fn parse_ops<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>) -> Result<Vec<Op>> {
+    let mut ops = vec![];
+    loop {
+        match lexer.next() {
+            Some("P") => {
+                let Some(token) = lexer.next_if(|token| token.starts_with('"')) else {
+                    eprintln!("ERROR: expected symbol after P");
+                    return Err(());
+                };
+                ops.push(Op::Write(0, symbol_from_token(token)?));
+            }
+            Some("->") => break,
+            Some("E") => ops.push(Op::Erase(0)),
+            Some("L") => ops.push(Op::Move(0, Step::Left)),
+            Some("R") => ops.push(Op::Move(0, Step::Right)),
+            Some("U") => ops.push(Op::Move(0, Step::Up)),
+            Some("D") => ops.push(Op::Move(0, Step::Down)),
+            Some(token) if token.len() > 1 && token.starts_with('P') => {
+                ops.push(Op::Write(0, Symbol::new(&token[1..])));
+            }
+            Some(token) => {
+                eprintln!("ERROR: expected operation P<symbol>, E, L, R, U, D or '->' but got {token}");
+                return Err(());
+            }
+            None => {
+                eprintln!("ERROR: expected '->' after operations but reached end of input");
+                return Err(());
+            }
         }
-        println!("^");
     }
+    Ok(ops)
 }
 
-fn parse_symbol<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<Symbol<'a>> {
-    if let Some(name) = lexer.next() {
-        Ok(Symbol{name})
+fn parse_case<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, program: &Program) -> Result<Template> {
+    let sets = &program.sets;
+    let mut params = vec![];
+    let state = parse_state(lexer, sets, &mut params)?;
+    let read = parse_per_tape(lexer, None, |lexer| parse_pattern(lexer, sets, &params))?;
+    let mut bound = vec![];
+    for pattern in &read {
+        if let Pattern::Bind(var, _) = pattern {
+            if bound.contains(&var) {
+                eprintln!("ERROR: variable ${var} is bound twice");
+                return Err(());
+            }
+            bound.push(var);
+        }
+    }
+    let ops = if lexer.next_if_eq(&":").is_some() {
+        if read.len() != 1 {
+            eprintln!("ERROR: operation sequences only work on single-tape cases");
+            return Err(());
+        }
+        parse_ops(lexer)?
     } else {
-        eprintln!("ERROR: expected symbol but reached end of input");
-        Err(())
+        let write = parse_per_tape(lexer, Some(read.len()), parse_write)?;
+        let step = parse_per_tape(lexer, Some(read.len()), parse_step)?;
+        let writes = write.into_iter().enumerate().filter_map(|(i, symbol)| Some(Op::Write(i, symbol?)));
+        let moves = step.into_iter().enumerate().map(|(i, step)| Op::Move(i, step));
+        writes.chain(moves).collect()
+    };
+    for op in &ops {
+        if let Op::Write(_, symbol) = op {
+            check_vars(&symbol.name, symbol.vars(), &read, &params)?;
+        }
+    }
+    let next = if lexer.next_if_eq(&"call").is_some() {
+        parse_call(lexer, program)?
+    } else {
+        Next::State(parse_state_ref(lexer)?)
+    };
+    check_vars(&next.to_string(), next.vars(), &read, &params)?;
+    let weight = if let Some(token) = lexer.next_if(|token| token.starts_with('@')) {
+        match token[1..].parse::<f64>() {
+            Ok(weight) if weight.is_finite() && weight > 0.0 => weight,
+            _ => {
+                eprintln!("ERROR: expected a positive weight but got {token}");
+                return Err(());
+            }
+        }
+    } else {
+        1.0
+    };
+    Ok(Template{params, case: Case{state, read, ops, next, weight}})
+}
+
+fn parse_set_decl<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, sets: &mut Sets) -> Result<()> {
+    let name = parse_symbol(lexer)?.name.to_string();
+    if sets.contains_key(&name) {
+        eprintln!("ERROR: set {name} is already declared");
+        return Err(());
     }
+    expect_token(lexer, "=")?;
+    let set = parse_set_expr(lexer, sets)?;
+    sets.insert(name, set);
+    Ok(())
 }
 
-fn parse_step<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<Step> {
-    let symbol = parse_symbol(lexer)?;
-    match symbol.name {
-        "->" => Ok(Step::Right),
-        "<-" => Ok(Step::Left),
-        name => {
-            eprintln!("ERROR: expected '->' or '<-' but got {name}");
-            Err(())
+// `accept State` and `reject State` declare one halting state each. The
+// state may be a family, which declares all of its members.
+fn parse_halt_decl<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, outcome: Outcome, program: &mut Program) -> Result<()> {
+    let mut params = vec![];
+    let state = parse_state(lexer, &program.sets, &mut params)?;
+    let mut result = Ok(());
+    for_each_binding(&params, &mut vec![], &mut |bindings| {
+        let state = state.substitute(bindings);
+        if program.outcome(&state).is_some() {
+            eprintln!("ERROR: halting state {state} is declared twice");
+            result = Err(());
+        }
+        program.halts.push((state, outcome));
+    });
+    result
+}
+
+fn parse_start_state<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>) -> Result<State> {
+    let state = parse_state_ref(lexer)?;
+    if let Some(var) = state.vars().first() {
+        eprintln!("ERROR: start state {state} may not use variable ${var}");
+        return Err(());
+    }
+    Ok(state)
+}
+
+// `import "path" as ns` brings in the sets and cases of another file, with
+// its states and sets renamed to `ns::Name`. The path is relative to the
+// importing file. The start and halting states of the imported file do not
+// halt the importer; they are where `call ns` enters and returns.
+fn parse_import<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, dir: &Path, importing: &mut Vec<PathBuf>, program: &mut Program) -> Result<()> {
+    let path = dir.join(parse_string(lexer)?);
+    expect_token(lexer, "as")?;
+    let namespace = parse_symbol(lexer)?.name;
+    if namespace.is_empty() || namespace.contains(|x: char| !(x.is_alphanumeric() || x == '_')) {
+        eprintln!("ERROR: invalid namespace {namespace}");
+        return Err(());
+    }
+    if program.modules.iter().any(|module| *module.namespace == *namespace) {
+        eprintln!("ERROR: namespace {namespace} is already imported");
+        return Err(());
+    }
+
+    let library = load_program(&path, importing).map_err(|()| {
+        eprintln!("ERROR: could not import {path}", path = path.display());
+    })?;
+    for (name, set) in library.sets {
+        program.sets.insert(format!("{namespace}::{name}"), set);
+    }
+    for case in library.cases {
+        program.cases.push(Case {
+            state: case.state.prefixed(&namespace),
+            next: case.next.prefixed(&namespace),
+            ..case
+        });
+    }
+    for module in library.modules {
+        program.modules.push(Module {
+            namespace: format!("{namespace}::{inner}", inner = module.namespace),
+            start: module.start.map(|start| start.prefixed(&namespace)),
+            exits: module.exits.iter().map(|exit| exit.prefixed(&namespace)).collect(),
+        });
+    }
+    program.modules.push(Module {
+        namespace: namespace.to_string(),
+        start: library.start.map(|start| start.prefixed(&namespace)),
+        exits: library.halts.iter().map(|(halt, _)| halt.prefixed(&namespace)).collect(),
+    });
+    Ok(())
+}
+
+fn parse_program<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, dir: &Path, importing: &mut Vec<PathBuf>) -> Result<Program> {
+    let mut program = Program::default();
+    while let Some(&token) = lexer.peek() {
+        match token {
+            "import" => {
+                lexer.next();
+                parse_import(lexer, dir, importing, &mut program)?;
+            }
+            "set" => {
+                lexer.next();
+                parse_set_decl(lexer, &mut program.sets)?;
+            }
+            "accept" => {
+                lexer.next();
+                parse_halt_decl(lexer, Outcome::Accepted, &mut program)?;
+            }
+            "reject" => {
+                lexer.next();
+                parse_halt_decl(lexer, Outcome::Rejected, &mut program)?;
+            }
+            "grid" => {
+                lexer.next();
+                program.grid = true;
+            }
+            "start" => {
+                lexer.next();
+                let state = parse_start_state(lexer)?;
+                if let Some(start) = &program.start {
+                    eprintln!("ERROR: start state is already declared as {start}");
+                    return Err(());
+                }
+                program.start = Some(state);
+            }
+            _ => {
+                let template = parse_case(lexer, &program)?;
+                template.expand(&mut program.cases);
+            }
+        }
+    }
+
+    Ok(program)
+}
+
+// `importing` holds the files that are being loaded, to catch import cycles.
+fn load_program(path: &Path, importing: &mut Vec<PathBuf>) -> Result<Program> {
+    let source = fs::read_to_string(path).map_err(|err| {
+        eprintln!("ERROR: could not read file {path}: {err}", path = path.display());
+    })?;
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if importing.contains(&canonical) {
+        eprintln!("ERROR: {path} imports itself", path = path.display());
+        return Err(());
+    }
+
+    importing.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut lexer = Lexer::new(&source);
+    let program = parse_program(&mut lexer.by_ref().peekable(), dir, importing);
+    importing.pop();
+    let program = program?;
+    lexer.finish()?;
+    Ok(program)
+}
+
+// A tape file holds one tape per `|`-separated section, or the rows of the
+// grid in grid mode.
+// `x*n` writes `x` out `n` times and `(x y)*n` does the same for a group.
+// The count may also follow a quoted symbol or a group as a separate token,
+// as long as nothing stands between them.
+fn parse_repeat_count(token: &str) -> Result<usize> {
+    token.parse().map_err(|_| {
+        eprintln!("ERROR: expected repeat count but got {token}");
+    })
+}
+
+// The cells of a tape file as written, with repeats left folded up. Tapes
+// are filled in from these directly, so a large count costs nothing until
+// the cells are stored, and runs of the default symbol are never stored.
+#[derive(Debug)]
+enum Cells {
+    Run(Symbol, usize),
+    Repeat(Vec<Cells>, usize),
+}
+
+impl Cells {
+    // Lengths are checked while parsing, so this cannot overflow.
+    fn len(cells: &[Cells]) -> usize {
+        cells.iter().map(|cells| match cells {
+            Cells::Run(_, count) => *count,
+            Cells::Repeat(body, count) => Cells::len(body) * count,
+        }).sum()
+    }
+
+    fn last(cells: &[Cells]) -> Option<&Symbol> {
+        cells.iter().rev().find_map(|cells| match cells {
+            Cells::Run(symbol, count) => Some(symbol).filter(|_| *count > 0),
+            Cells::Repeat(body, count) => Cells::last(body).filter(|_| *count > 0),
+        })
+    }
+
+    fn is_blank(cells: &[Cells], default: &Symbol) -> bool {
+        cells.iter().all(|cells| match cells {
+            Cells::Run(symbol, count) => symbol == default || *count == 0,
+            Cells::Repeat(body, count) => Cells::is_blank(body, default) || *count == 0,
+        })
+    }
+
+    fn repeat(self, times: usize) -> Cells {
+        match self {
+            Cells::Run(symbol, count) => Cells::Run(symbol, count * times),
+            Cells::Repeat(body, count) => Cells::Repeat(body, count * times),
+        }
+    }
+
+    // Stores the cells into row `y` of the tape from column `x` onwards.
+    fn store(cells: &[Cells], tape: &mut Tape, x: &mut isize, y: isize) {
+        if Cells::is_blank(cells, &tape.default) {
+            *x += Cells::len(cells) as isize;
+            return;
+        }
+        for cells in cells {
+            match cells {
+                Cells::Run(symbol, count) => {
+                    if *symbol == tape.default {
+                        *x += *count as isize;
+                        continue;
+                    }
+                    for _ in 0..*count {
+                        tape.set(*x, y, symbol.clone());
+                        *x += 1;
+                    }
+                }
+                Cells::Repeat(body, count) => {
+                    for _ in 0..*count {
+                        Cells::store(body, tape, x, y);
+                    }
+                }
+            }
         }
     }
 }
 
-fn parse_case<'a>(lexer: &mut impl Iterator<Item = &'a str>) -> Result<Case<'a>> {
-    let state = parse_symbol(lexer)?;
-    let read = parse_symbol(lexer)?;
-    let write = parse_symbol(lexer)?;
-    let step = parse_step(lexer)?;
-    let next = parse_symbol(lexer)?;
-    Ok(Case{state, read, write, step, next})
+// Positions on a tape are `isize`, so that is as long as a tape may get.
+fn grow_tape(len: usize, by: usize) -> Result<usize> {
+    len.checked_add(by).filter(|&len| len <= isize::MAX as usize).ok_or_else(|| {
+        eprintln!("ERROR: tape is too long.");
+    })
 }
 
-fn parse_cases<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>) -> Result<Vec<Case<'a>>> {
-    let mut cases = vec![];
-    while lexer.peek().is_some() {
-        cases.push(parse_case(lexer)?);
+fn mark_head(head: &mut Option<usize>, at: usize) -> Result<()> {
+    if head.is_some() {
+        eprintln!("ERROR: a tape may only mark the head once.");
+        return Err(());
     }
+    *head = Some(at);
+    Ok(())
+}
+
+// `[x]` marks the cell the head starts on, which is at the start of the
+// tape otherwise. `len` counts the cells read so far, so the head can be
+// placed without writing the cells out.
+fn parse_cells<'a>(
+    lexer: &mut Peekable<impl Iterator<Item = &'a str>>,
+    cells: &mut Vec<Cells>,
+    len: &mut usize,
+    head: &mut Option<usize>,
+) -> Result<()> {
+    while let Some(token) = lexer.next_if(|token| *token != "|" && *token != ")") {
+        let start = *len;
+        let mut count = None;
+        let (item, last) = if token == "(" {
+            let mut body = vec![];
+            parse_cells(lexer, &mut body, len, head)?;
+            let Some(close) = lexer.next() else {
+                eprintln!("ERROR: unclosed group in tape");
+                return Err(());
+            };
+            if close != ")" {
+                eprintln!("ERROR: expected ) but got {close}");
+                return Err(());
+            }
+            (Cells::Repeat(body, 1), close)
+        } else if let Some(name) = token.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')).filter(|name| !name.is_empty()) {
+            mark_head(head, start)?;
+            (Cells::Run(Symbol::new(name), 1), token)
+        } else if let Some(quoted) = lexer.next_if(|next| token == "[" && next.starts_with('"') && adjacent(token, next)) {
+            let Some(close) = lexer.next_if(|next| next.starts_with(']') && adjacent(quoted, next)) else {
+                eprintln!("ERROR: expected ] after [{quoted}");
+                return Err(());
+            };
+            // The count of `["x"]*n` lexes together with the bracket.
+            if let Some(times) = close.strip_prefix("]*") {
+                count = Some(parse_repeat_count(times)?);
+            } else if close != "]" {
+                eprintln!("ERROR: expected ] after [{quoted} but got {close}");
+                return Err(());
+            }
+            mark_head(head, start)?;
+            (Cells::Run(symbol_from_token(quoted)?, 1), close)
+        } else if let Some((name, times)) = token.rsplit_once('*').filter(|(name, times)| {
+            !name.is_empty() && !times.is_empty() && times.bytes().all(|x| x.is_ascii_digit())
+        }) {
+            count = Some(parse_repeat_count(times)?);
+            // `[x]*n` marks the head too, which the count then rejects.
+            let marked = name.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')).filter(|name| !name.is_empty());
+            if marked.is_some() {
+                mark_head(head, start)?;
+            }
+            (Cells::Run(Symbol::new(marked.unwrap_or(name)), 1), token)
+        } else {
+            (Cells::Run(symbol_from_token(token)?, 1), token)
+        };
+        if !matches!(item, Cells::Repeat(..)) {
+            *len = grow_tape(*len, 1)?;
+        }
 
-    Ok(cases)
+        if let Some(times) = lexer.next_if(|next| count.is_none() && next.starts_with('*') && adjacent(last, next)) {
+            count = Some(parse_repeat_count(&times[1..])?);
+        }
+        let Some(count) = count else {
+            cells.push(item);
+            continue;
+        };
+        if head.is_some_and(|head| head >= start) {
+            eprintln!("ERROR: the head marker may not be repeated.");
+            return Err(());
+        }
+        let repeated = (*len - start).checked_mul(count).ok_or_else(|| {
+            eprintln!("ERROR: tape is too long.");
+        })?;
+        *len = grow_tape(start, repeated)?;
+        cells.push(item.repeat(count));
+    }
+    Ok(())
 }
 
-fn parse_tape<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>) -> Result<Vec<Symbol<'a>>> {
-    let mut symbols = vec![];
-    while lexer.peek().is_some() {
-        symbols.push(parse_symbol(lexer)?);
+fn parse_tapes<'a>(lexer: &mut Peekable<impl Iterator<Item = &'a str>>, grid: bool) -> Result<Vec<Tape>> {
+    let mut sections = vec![];
+    loop {
+        let mut cells = vec![];
+        let mut len = 0;
+        let mut head = None;
+        parse_cells(lexer, &mut cells, &mut len, &mut head)?;
+        if lexer.next_if_eq(&")").is_some() {
+            eprintln!("ERROR: unexpected ) in tape");
+            return Err(());
+        }
+        if len == 0 {
+            let what = if grid { "row" } else { "tape" };
+            eprintln!("ERROR: {what} {n} may not be empty.", n = sections.len() + 1);
+            return Err(());
+        }
+        sections.push((cells, head));
+        if lexer.next().is_none() {
+            break;
+        }
+    }
+
+    if !grid {
+        let tapes = sections.into_iter().filter_map(|(section, head)| {
+            let mut tape = Tape::new(&[section])?;
+            tape.x = head.unwrap_or(0) as isize;
+            Some(tape)
+        });
+        return Ok(tapes.collect());
     }
 
-    Ok(symbols)
+    let mut marks = sections.iter().enumerate().filter_map(|(y, (_, head))| Some((head.as_ref()?, y)));
+    let (x, y) = marks.next().map_or((0, 0), |(&x, y)| (x, y));
+    if marks.next().is_some() {
+        eprintln!("ERROR: a grid may only mark the head once.");
+        return Err(());
+    }
+    let rows: Vec<Vec<Cells>> = sections.into_iter().map(|(row, _)| row).collect();
+    Ok(Tape::new(&rows).into_iter().map(|mut tape| {
+        tape.x = x as isize;
+        tape.y = y as isize;
+        tape
+    }).collect())
 }
 
 fn usage(program: &str) {
-    eprintln!("usage: {program} <input.alan> <input.tape>");
+    eprintln!("usage: {program} [options] <input.alan> <input.tape>");
+    eprintln!("options:");
+    eprintln!("    --start <state>      start in <state> instead of the declared start state");
+    eprintln!("    --nondet             explore every matching case instead of the first one");
+    eprintln!("    --detect-loops       stop when the machine repeats a configuration");
+    eprintln!("    --max-depth <n>      with --nondet, give up after <n> steps");
+    eprintln!("    --max-configs <n>    with --nondet, give up after <n> configurations");
+    eprintln!("    --seed <n>           pick between matching cases at random by their weights");
+    eprintln!("    --max-steps <n>      stop after <n> steps");
+    eprintln!("    --timeout <secs>     stop after <secs> seconds");
+    eprintln!("    --max-tape <n>       stop once a tape covers more than <n> cells");
+}
+
+fn parse_flag_number(program: &str, flag: &str, value: Option<String>) -> Result<usize> {
+    let Some(value) = value else {
+        usage(program);
+        eprintln!("ERROR: no number provided for {flag}.");
+        return Err(());
+    };
+    value.parse().map_err(|_| {
+        usage(program);
+        eprintln!("ERROR: {flag} expects a number but got {value}.");
+    })
+}
+
+fn print_halt(outcome: Outcome, machine: &Machine) {
+    let state = &machine.state;
+    match outcome {
+        Outcome::Accepted => println!("ACCEPTED: {state}"),
+        Outcome::Rejected => println!("REJECTED: {state}"),
+        Outcome::Halted | Outcome::Stopped | Outcome::Looping => {}
+    }
+    machine.print_visited();
+}
+
+// Bounds on a run from the command line, so machines that never halt can
+// still be run unattended.
+#[derive(Debug, Default)]
+struct Limits {
+    max_steps: Option<usize>,
+    timeout: Option<Duration>,
+    max_tape: Option<usize>,
+}
+
+impl Limits {
+    // Says which limit the machine has run into, if any.
+    fn check(&self, machine: &Machine, steps: usize, started: Instant) -> Option<String> {
+        if let Some(max) = self.max_steps.filter(|&max| steps >= max) {
+            return Some(format!("--max-steps {max} reached"));
+        }
+        if let Some(timeout) = self.timeout.filter(|&timeout| started.elapsed() >= timeout) {
+            return Some(format!("--timeout {secs} reached", secs = timeout.as_secs()));
+        }
+        if let Some(max) = self.max_tape {
+            for (i, tape) in machine.tapes.iter().enumerate() {
+                if tape.size() > max {
+                    let which = if machine.tapes.len() == 1 { "tape".to_string() } else { format!("tape {n}", n = i + 1) };
+                    return Some(format!("{which} grew past --max-tape {max}"));
+                }
+            }
+        }
+        None
+    }
+}
+
+// With `detect_loops`, the run looks for a repeated configuration with
+// Brent's algorithm: a single saved configuration is compared against every
+// new one and moved up to the current one each time the distance between them
+// reaches the next power of two. A match gives the length of the cycle, and
+// the step where it starts is found by replaying the run from the beginning.
+fn run(mut machine: Machine, alan: &Program, mut rng: Option<Rng>, limits: &Limits, detect_loops: bool) -> Result<Outcome> {
+    let started = Instant::now();
+    let mut steps = 0;
+    let mut table = Table::new(alan);
+    let initial = if detect_loops { Some(machine.clone()) } else { None };
+    let mut saved = initial.clone().map(|saved| (saved, 0));
+    let mut power = 1;
+    loop {
+        machine.print();
+        if let Some(outcome) = table.outcome(machine.state) {
+            print_halt(outcome, &machine);
+            return Ok(outcome);
+        }
+        if let Some(reason) = limits.check(&machine, steps, started) {
+            println!("STOPPED: {reason} after {steps} steps in state {state} reading {symbols}", state = machine.state, symbols = machine.reading());
+            print_halt(Outcome::Stopped, &machine);
+            return Ok(Outcome::Stopped);
+        }
+        if let (Some(initial), Some((saved, at))) = (&initial, &mut saved) {
+            if steps > *at && *saved == machine {
+                let length = steps - *at;
+                let first = cycle_start(initial, length, &alan.cases, &mut table);
+                println!("LOOPS FOREVER: a cycle of {length} steps starting at step {first}");
+                print_halt(Outcome::Looping, &machine);
+                return Ok(Outcome::Looping);
+            }
+            if steps - *at == power {
+                *saved = machine.clone();
+                *at = steps;
+                power *= 2;
+            }
+        }
+        steps += 1;
+        machine.halt = true;
+        match &mut rng {
+            Some(rng) => machine.next_random(&alan.cases, &mut table, rng),
+            None => machine.next(&alan.cases, &mut table),
+        }
+        if machine.halt {
+            break;
+        }
+    }
+
+    if !alan.halts.is_empty() {
+        eprintln!("ERROR: no case for state {state} reading {symbols}", state = machine.state, symbols = machine.reading());
+        return Err(());
+    }
+    print_halt(Outcome::Halted, &machine);
+    Ok(Outcome::Halted)
+}
+
+// Runs two copies of the machine `length` steps apart until they meet, which
+// they first do where the cycle starts.
+fn cycle_start(initial: &Machine, length: usize, cases: &[Case], table: &mut Table) -> usize {
+    let mut tortoise = initial.clone();
+    let mut hare = initial.clone();
+    for _ in 0..length {
+        hare.next(cases, table);
+    }
+    let mut start = 0;
+    while tortoise != hare {
+        tortoise.next(cases, table);
+        hare.next(cases, table);
+        start += 1;
+    }
+    start
+}
+
+// Explores every matching case breadth-first and accepts as soon as any
+// branch does, printing the path that led there. Configurations that were
+// seen before are not explored again, and branches that get stuck or reject
+// are dropped.
+fn run_nondeterministic(machine: Machine, alan: &Program, max_depth: Option<usize>, max_configs: Option<usize>, timeout: Option<Duration>) -> Result<Outcome> {
+    if !alan.halts.iter().any(|(_, outcome)| matches!(outcome, Outcome::Accepted)) {
+        eprintln!("ERROR: --nondet needs at least one `accept` state.");
+        return Err(());
+    }
+
+    let started = Instant::now();
+    let mut table = Table::new(alan);
+    let mut seen = HashSet::new();
+    seen.insert(machine.clone());
+    // Every configuration reached so far, with the index of its parent.
+    let mut nodes = vec![(machine, None)];
+    let mut frontier = vec![0];
+    let mut depth = 0;
+    while !frontier.is_empty() {
+        if let Some(&accepted) = frontier.iter().find(|&&i| matches!(table.outcome(nodes[i].0.state), Some(Outcome::Accepted))) {
+            let mut path = vec![];
+            let mut at = Some(accepted);
+            while let Some(i) = at {
+                path.push(&nodes[i].0);
+                at = nodes[i].1;
+            }
+            for machine in path.iter().rev() {
+                machine.print();
+            }
+            print_halt(Outcome::Accepted, &nodes[accepted].0);
+            return Ok(Outcome::Accepted);
+        }
+        if max_depth.is_some_and(|max| depth >= max) {
+            println!("STOPPED: no branch accepts within {depth} steps");
+            return Ok(Outcome::Stopped);
+        }
+        if let Some(timeout) = timeout.filter(|&timeout| started.elapsed() >= timeout) {
+            println!("STOPPED: no branch accepts within --timeout {secs} ({depth} steps)", secs = timeout.as_secs());
+            return Ok(Outcome::Stopped);
+        }
+
+        let mut next_frontier = vec![];
+        for &i in &frontier {
+            if table.outcome(nodes[i].0.state).is_some() {
+                continue;
+            }
+            let candidates = table.candidates(&nodes[i].0, &alan.cases);
+            for &case in &table.cases[candidates] {
+                let mut machine = nodes[i].0.clone();
+                machine.apply(&alan.cases[case], table.jumps[case].as_ref());
+                if seen.insert(machine.clone()) {
+                    if max_configs.is_some_and(|max| nodes.len() >= max) {
+                        println!("STOPPED: no branch accepts within {n} configurations", n = nodes.len());
+                        return Ok(Outcome::Stopped);
+                    }
+                    next_frontier.push(nodes.len());
+                    nodes.push((machine, Some(i)));
+                }
+            }
+        }
+        frontier = next_frontier;
+        depth += 1;
+    }
+
+    println!("REJECTED: no branch accepts");
+    Ok(Outcome::Rejected)
 }
 
-fn start() -> Result<()> {
+fn start() -> Result<Outcome> {
     let mut args = env::args();
     let program = args.next().expect("program name is always present.");
 
+    let mut start_arg = None;
+    let mut nondet = false;
+    let mut detect_loops = false;
+    let mut max_depth = None;
+    let mut max_configs = None;
+    let mut seed = None;
+    let mut limits = Limits::default();
+    let mut paths = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--nondet" => nondet = true,
+            "--detect-loops" => detect_loops = true,
+            "--max-depth" => max_depth = Some(parse_flag_number(&program, &arg, args.next())?),
+            "--max-configs" => max_configs = Some(parse_flag_number(&program, &arg, args.next())?),
+            "--seed" => seed = Some(parse_flag_number(&program, &arg, args.next())? as u64),
+            "--max-steps" => limits.max_steps = Some(parse_flag_number(&program, &arg, args.next())?),
+            "--timeout" => limits.timeout = Some(Duration::from_secs(parse_flag_number(&program, &arg, args.next())? as u64)),
+            "--max-tape" => limits.max_tape = Some(parse_flag_number(&program, &arg, args.next())?),
+            "--start" => {
+                if let Some(state) = args.next() {
+                    start_arg = Some(state);
+                } else {
+                    usage(&program);
+                    eprintln!("ERROR: no state provided for --start.");
+                    return Err(());
+                }
+            }
+            flag if flag.starts_with("--") => {
+                usage(&program);
+                eprintln!("ERROR: unknown flag {flag}.");
+                return Err(());
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if !nondet && (max_depth.is_some() || max_configs.is_some()) {
+        usage(&program);
+        eprintln!("ERROR: --max-depth and --max-configs only apply with --nondet.");
+        return Err(());
+    }
+    if nondet && seed.is_some() {
+        usage(&program);
+        eprintln!("ERROR: --seed does not apply with --nondet.");
+        return Err(());
+    }
+    if detect_loops && (nondet || seed.is_some()) {
+        usage(&program);
+        eprintln!("ERROR: --detect-loops only applies to deterministic runs.");
+        return Err(());
+    }
+    if nondet && (limits.max_steps.is_some() || limits.max_tape.is_some()) {
+        usage(&program);
+        eprintln!("ERROR: --max-steps and --max-tape do not apply with --nondet; use --max-depth and --max-configs.");
+        return Err(());
+    }
+    let mut args = paths.into_iter();
+
     let alan_path;
     if let Some(path) = args.next() {
         alan_path = path;
@@ -145,10 +2195,7 @@ fn start() -> Result<()> {
         eprintln!("ERROR: no input.alan provided.");
         return Err(());
     }
-    let alan_source = fs::read_to_string(alan_path.clone()).map_err(|err| {
-        eprintln!("ERROR: could not read file {alan_path}: {err}");
-    })?;
-    let cases = parse_cases(&mut alan_source.split(&[' ', '\n']).filter(|t| t.len() > 0).peekable())?;
+    let alan = load_program(Path::new(&alan_path), &mut vec![])?;
 
     let tape_path;
     if let Some(path) = args.next() {
@@ -161,36 +2208,126 @@ fn start() -> Result<()> {
     let tape_source = fs::read_to_string(tape_path.clone()).map_err(|err| {
         eprintln!("ERROR: could not read file {tape_path}: {err}");
     })?;
-    let tape = parse_tape(&mut tape_source.split(&[' ', '\n']).filter(|t| t.len() > 0).peekable())?;
+    let mut lexer = Lexer::new(&tape_source);
+    let tapes = parse_tapes(&mut lexer.by_ref().peekable(), alan.grid)?;
+    lexer.finish()?;
+    if !alan.grid {
+        if let Some(case) = alan.cases.iter().find(|case| case.ops.iter().any(|op| matches!(op, Op::Move(_, Step::Up | Step::Down)))) {
+            eprintln!("ERROR: a case of {state} moves up or down, which needs the `grid` directive.", state = case.state);
+            return Err(());
+        }
+    }
+    if let Some(case) = alan.cases.iter().find(|case| case.read.len() != tapes.len()) {
+        eprintln!("ERROR: a case of {state} works on {n} tapes but {tape_path} has {m}.", state = case.state, n = case.read.len(), m = tapes.len());
+        return Err(());
+    }
 
-    let tape_default;
-    if let Some(symbol) = tape.last() {
-        tape_default = symbol;
+    let state = if let Some(source) = &start_arg {
+        let mut lexer = Lexer::new(source).peekable();
+        let state = parse_start_state(&mut lexer)?;
+        if let Some(token) = lexer.next() {
+            eprintln!("ERROR: unexpected {token} after start state {state}");
+            return Err(());
+        }
+        state
+    } else if let Some(state) = &alan.start {
+        state.clone()
     } else {
-        eprintln!("ERROR: tape file may not be empty.");
+        eprintln!("ERROR: no start state: declare one with `start <state>` in {alan_path} or pass --start <state>.");
+        return Err(());
+    };
+    if !alan.mentions(&state) {
+        eprintln!("ERROR: start state {state} has no cases and is not a halting state.");
         return Err(());
     }
 
-    let mut machine = Machine {
-        state: Symbol{name: "Inc"},
-        tape,
-        tape_default,
-        head: 0,
+    let machine = Machine {
+        state: StateId::of(&state),
+        tapes,
         halt: false,
+        stack: vec![],
     };
 
-    while !machine.halt {
-        machine.print();
-        machine.halt = true;
-        machine.next(&cases)?;
+    if nondet {
+        run_nondeterministic(machine, &alan, max_depth, max_configs, limits.timeout)
+    } else {
+        run(machine, &alan, seed.map(Rng::new), &limits, detect_loops)
     }
-
-    Ok(())
 }
 
+// Exit codes: 0 when the machine accepts (or halts in a program without
+// halting states), 1 on errors, 2 when the machine rejects, 3 when a limit
+// stops the run and 4 when the machine is found to loop forever.
 fn main() -> ExitCode {
     match start() {
-        Ok(()) => ExitCode::SUCCESS,
+        Ok(Outcome::Halted | Outcome::Accepted) => ExitCode::SUCCESS,
+        Ok(Outcome::Rejected) => ExitCode::from(2),
+        Ok(Outcome::Stopped) => ExitCode::from(3),
+        Ok(Outcome::Looping) => ExitCode::from(4),
         Err(()) => ExitCode::FAILURE,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> (Vec<&str>, Result<()>) {
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.by_ref().collect();
+        (tokens, lexer.finish())
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(tokens("a /* b /* c */ d */ e"), (vec!["a", "e"], Ok(())));
+    }
+
+    #[test]
+    fn line_comments_end_tokens() {
+        assert_eq!(tokens("a#b c\nd"), (vec!["a", "d"], Ok(())));
+        assert_eq!(tokens("a//b\nc"), (vec!["a", "c"], Ok(())));
+    }
+
+    #[test]
+    fn strings_keep_escaped_quotes() {
+        let (tokens, result) = tokens(r#"x"a\"b"y"#);
+        assert_eq!((tokens.clone(), result), (vec!["x", r#""a\"b""#, "y"], Ok(())));
+        assert_eq!(unquote(tokens[1]), Ok("a\"b".to_string()));
+        assert_eq!(unquote(r#""\\\n\t""#), Ok("\\\n\t".to_string()));
+        assert_eq!(unquote(r#""\q""#), Err(()));
+    }
+
+    #[test]
+    fn unclosed_string_fails() {
+        assert_eq!(tokens(r#"a "b c"#), (vec!["a", r#""b c"#], Err(())));
+        assert_eq!(unquote(r#""b c"#), Err(()));
+        assert_eq!(tokens(r#"0 "abc\""#), (vec!["0", r#""abc\""#], Err(())));
+        assert_eq!(unquote(r#""abc\""#), Err(()));
+    }
+
+    #[test]
+    fn unclosed_comment_fails() {
+        assert_eq!(tokens("a /* b /* c */"), (vec!["a"], Err(())));
+    }
+
+    #[test]
+    fn families_need_adjacent_parentheses() {
+        let mut sets = Sets::new();
+        sets.insert("D".to_string(), vec![Symbol::new("0"), Symbol::new("1")]);
+
+        let mut params = vec![];
+        let mut lexer = Lexer::new("Carry(d in D) x").peekable();
+        let state = parse_state(&mut lexer, &sets, &mut params).unwrap();
+        assert_eq!(params.len(), 1);
+        assert_eq!(state.to_string(), "Carry($d)");
+        assert_eq!(lexer.next(), Some("x"));
+
+        let mut params = vec![];
+        let mut lexer = Lexer::new("Carry (d in D) x").peekable();
+        let state = parse_state(&mut lexer, &sets, &mut params).unwrap();
+        assert!(params.is_empty());
+        assert_eq!(state.to_string(), "Carry");
+        assert_eq!(lexer.next(), Some("("));
+    }
+}